@@ -0,0 +1,88 @@
+//! Generate the entity-matching trie used by `src/unescape.rs`.
+//!
+//! The normative list of [named character references](https://html.spec.whatwg.org/multipage/named-characters.html#named-character-references)
+//! is published by the WHATWG as `entities.json`, a map from the reference
+//! (including the leading `&` and any trailing `;`) to its expansion. We turn
+//! it into a byte-indexed trie: each node is keyed by the next byte after `&`,
+//! an edge advances to a child node, and a node optionally carries the decoded
+//! expansion for the entity ending there. Emitting it as static arrays lets
+//! `match_entity` walk the reference with plain array indexing — no hashing and
+//! no length scan — at a cost proportional to the matched prefix length.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// A node in the trie while it is being built.
+#[derive(Default)]
+struct Node {
+    /// Outgoing edges keyed by the next byte; the value is the child node’s
+    /// index. `BTreeMap` keeps them sorted so the emitted edge lists can be
+    /// binary-searched.
+    edges: BTreeMap<u8, usize>,
+
+    /// The expansion for the entity ending at this node, if any.
+    expansion: Option<String>,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=entities.json");
+
+    let json =
+        fs::read_to_string("entities.json").expect("could not read entities.json");
+    let parsed: serde_json::Value =
+        serde_json::from_str(&json).expect("could not parse entities.json");
+    let entities = parsed.as_object().expect("entities.json is not an object");
+
+    let mut nodes: Vec<Node> = vec![Node::default()];
+    let mut max_length = 0;
+
+    for (reference, definition) in entities {
+        // `reference` includes the leading `&` (and a trailing `;` when the
+        // entity requires one); its byte length is the longest possible
+        // candidate for that entity.
+        max_length = max_length.max(reference.len());
+
+        let characters = definition["characters"]
+            .as_str()
+            .expect("entity definition is missing `characters`");
+
+        let mut node = 0;
+        for &byte in &reference.as_bytes()[1..] {
+            node = match nodes[node].edges.get(&byte) {
+                Some(&child) => child,
+                None => {
+                    let child = nodes.len();
+                    nodes.push(Node::default());
+                    nodes[node].edges.insert(byte, child);
+                    child
+                }
+            };
+        }
+
+        nodes[node].expansion = Some(characters.to_owned());
+    }
+
+    let mut out = String::new();
+    out.push_str(&format!(
+        "const ENTITY_MAX_LENGTH: usize = {max_length};\n\n"
+    ));
+    out.push_str("static ENTITY_NODES: &[TrieNode] = &[\n");
+    for node in &nodes {
+        out.push_str("    TrieNode { edges: &[");
+        for (byte, child) in &node.edges {
+            out.push_str(&format!("({byte}, {child}), "));
+        }
+        out.push_str("], expansion: ");
+        match &node.expansion {
+            Some(expansion) => out.push_str(&format!("Some({expansion:?})")),
+            None => out.push_str("None"),
+        }
+        out.push_str(" },\n");
+    }
+    out.push_str("];\n");
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("entities.rs");
+    fs::write(dest, out).expect("could not write generated entities.rs");
+}
@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
 #[inline]
 fn map_u8(c: u8) -> &'static [u8] {
     match c {
@@ -26,6 +29,54 @@ macro_rules! escape {
     }}
 }
 
+macro_rules! escape_cow {
+    ($raw:expr, $($ch:literal),+) => {{
+        let raw = $raw;
+        let bytes = raw.as_bytes();
+
+        if !bytes.iter().any(|c| matches!(c, $($ch)|+)) {
+            // Nothing to escape: hand back the input without allocating.
+            return Cow::Borrowed(raw);
+        }
+
+        let mut output: Vec<u8> = Vec::with_capacity(bytes.len());
+        for c in bytes {
+            match c {
+                $($ch)|+ => output.extend_from_slice(map_u8(*c)),
+                _ => output.push(*c),
+            }
+        }
+
+        Cow::Owned(String::from_utf8(output).unwrap())
+    }}
+}
+
+fn escape_stream_impl<R, W>(
+    input: R,
+    output: W,
+    should_escape: impl Fn(u8) -> bool,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    // Escaping needs no lookahead, so each byte can be translated as it
+    // arrives, letting arbitrarily large inputs stream with bounded memory.
+    let reader = BufReader::new(input);
+    let mut writer = BufWriter::new(output);
+
+    for byte in reader.bytes() {
+        let byte = byte?;
+        if should_escape(byte) {
+            writer.write_all(map_u8(byte))?;
+        } else {
+            writer.write_all(&[byte])?;
+        }
+    }
+
+    writer.flush()
+}
+
 /// Escape a string used in a text node, i.e. regular text.
 ///
 /// **Do not use this in attributes.**
@@ -42,6 +93,15 @@ pub fn escape_text<S: AsRef<[u8]>>(raw: S) -> String {
     escape!(raw, b'&', b'<', b'>')
 }
 
+/// Escape a string used in a text node, returning [`Cow::Borrowed`] when there
+/// is nothing to escape.
+///
+/// This avoids an allocation (and a UTF-8 revalidation) for the common case of
+/// already-clean text. See [`escape_text()`].
+pub fn escape_text_cow(raw: &str) -> Cow<'_, str> {
+    escape_cow!(raw, b'&', b'<', b'>')
+}
+
 /// Escape a string to be used in a quoted attribute.
 ///
 /// ```rust
@@ -56,6 +116,38 @@ pub fn escape_attribute<S: AsRef<[u8]>>(raw: S) -> String {
     escape!(raw, b'&', b'<', b'>', b'"')
 }
 
+/// Escape a string used in a quoted attribute, returning [`Cow::Borrowed`] when
+/// there is nothing to escape.
+///
+/// See [`escape_attribute()`] and [`escape_text_cow()`].
+pub fn escape_attribute_cow(raw: &str) -> Cow<'_, str> {
+    escape_cow!(raw, b'&', b'<', b'>', b'"')
+}
+
+/// Escape a text node, reading from `input` and writing to `output`.
+///
+/// See [`escape_text()`]. This streams the input so multi-megabyte documents
+/// can be escaped with bounded memory.
+pub fn escape_text_stream<R: Read, W: Write>(
+    input: R,
+    output: W,
+) -> io::Result<()> {
+    escape_stream_impl(input, output, |c| matches!(c, b'&' | b'<' | b'>'))
+}
+
+/// Escape a quoted attribute value, reading from `input` and writing to
+/// `output`.
+///
+/// See [`escape_attribute()`].
+pub fn escape_attribute_stream<R: Read, W: Write>(
+    input: R,
+    output: W,
+) -> io::Result<()> {
+    escape_stream_impl(input, output, |c| {
+        matches!(c, b'&' | b'<' | b'>' | b'"')
+    })
+}
+
 /// Escape a string including both single and double quotes.
 ///
 /// Generally, it is safe to leave single quotes (apostrophes) unescaped, so you
@@ -73,6 +165,27 @@ pub fn escape_all_quotes<S: AsRef<[u8]>>(raw: S) -> String {
     escape!(raw, b'&', b'<', b'>', b'"', b'\'')
 }
 
+/// Escape a string including both single and double quotes, returning
+/// [`Cow::Borrowed`] when there is nothing to escape.
+///
+/// See [`escape_all_quotes()`] and [`escape_text_cow()`].
+pub fn escape_all_quotes_cow(raw: &str) -> Cow<'_, str> {
+    escape_cow!(raw, b'&', b'<', b'>', b'"', b'\'')
+}
+
+/// Escape a string including both single and double quotes, reading from
+/// `input` and writing to `output`.
+///
+/// See [`escape_all_quotes()`].
+pub fn escape_all_quotes_stream<R: Read, W: Write>(
+    input: R,
+    output: W,
+) -> io::Result<()> {
+    escape_stream_impl(input, output, |c| {
+        matches!(c, b'&' | b'<' | b'>' | b'"' | b'\'')
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -105,6 +218,38 @@ mod tests {
             == "He said, &quot;That&apos;s mine.&quot;"
     );
 
+    test!(
+        escape_text_cow_borrows_clean,
+        matches!(escape_text_cow("clean"), Cow::Borrowed("clean"))
+    );
+    test!(
+        escape_text_cow_owns_dirty,
+        matches!(escape_text_cow("< >"), Cow::Owned(s) if s == "&lt; &gt;")
+    );
+    test!(
+        escape_attribute_cow_borrows_clean,
+        matches!(escape_attribute_cow("it's fine"), Cow::Borrowed(_))
+    );
+    test!(
+        escape_all_quotes_cow_owns_quote,
+        matches!(escape_all_quotes_cow("it's"), Cow::Owned(s) if s == "it&apos;s")
+    );
+
+    fn escape_text_stream_str(input: &str) -> String {
+        let mut output = Vec::new();
+        escape_text_stream(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    test!(
+        escape_text_stream_basic,
+        escape_text_stream_str("< >") == "&lt; &gt;"
+    );
+    test!(
+        escape_text_stream_clean,
+        escape_text_stream_str("clean") == "clean"
+    );
+
     const HTML_DIRTY: &str = include_str!("../tests/corpus/html-raw.txt");
     const HTML_DIRTY_ESCAPED: &str =
         include_str!("../tests/corpus/html-escaped.txt");
@@ -5,31 +5,165 @@
 // are prefixes for multiple other entities. For example:
 //   &times &times; &timesb; &timesbar; &timesd;
 
+use std::borrow::Cow;
 use std::char;
-use std::cmp::min;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::iter::Peekable;
 
-// Include the ENTITIES map generated by build.rs
+/// A node in the byte-indexed entity-matching trie.
+///
+/// The table (`ENTITY_NODES`, with the root at index 0, and `ENTITY_MAX_LENGTH`)
+/// is generated by `build.rs` as static arrays so matching is cache-friendly
+/// array indexing with no hashing.
+struct TrieNode {
+    /// Outgoing edges keyed by the next byte, sorted ascending so they can be
+    /// binary-searched. Each value indexes into `ENTITY_NODES`.
+    edges: &'static [(u8, u32)],
+
+    /// The decoded string for the entity ending at this node, if any.
+    expansion: Option<&'static str>,
+}
+
+// Include the entity trie generated by build.rs.
 include!(concat!(env!("OUT_DIR"), "/entities.rs"));
 
-/// Expand all valid entities
+/// Where unescaped text is being expanded.
+///
+/// The WHATWG [named character reference state](https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state)
+/// treats a reference that lacks a trailing semicolon differently inside an
+/// attribute value, so that query strings like `?a=1&copy=2` survive intact.
+/// Pass the appropriate context to [`unescape_in()`] to select the right
+/// behavior; [`unescape()`] always uses [`Context::Text`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Context {
+    /// A text node, i.e. regular content between tags.
+    Text,
+
+    /// A quoted attribute value.
+    Attribute,
+}
+
+/// The kind of a recoverable parse error encountered while unescaping.
+///
+/// These mirror a subset of the [parse errors](https://html.spec.whatwg.org/multipage/parsing.html#parse-errors)
+/// that the WHATWG spec defines for character references. They are reported by
+/// [`unescape_with_errors()`]; the lenient expansion behavior is unchanged.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseErrorKind {
+    /// A reference was not terminated by a semicolon.
+    MissingSemicolon,
+
+    /// A numeric reference resolved to a control character.
+    ControlCharacterReference,
+
+    /// A numeric reference resolved to a surrogate code point.
+    SurrogateCharacterReference,
+
+    /// A numeric reference resolved to a noncharacter code point.
+    NoncharacterCharacterReference,
+
+    /// A numeric reference was outside the Unicode range.
+    CharacterReferenceOutsideUnicodeRange,
+
+    /// A numeric reference resolved to U+0000.
+    NullCharacterReference,
+}
+
+/// A recoverable parse error and where it occurred.
+///
+/// See [`unescape_with_errors()`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ParseError {
+    /// What went wrong.
+    pub kind: ParseErrorKind,
+
+    /// The byte offset of the `&` that started the offending reference.
+    pub offset: usize,
+}
+
+/// A sink for [`ParseError`]s that either collects or ignores them.
+///
+/// [`unescape()`] uses [`Sink::Ignore`] so it does not allocate, while
+/// [`unescape_with_errors()`] collects into a caller-supplied `Vec`.
+enum Sink<'a> {
+    Ignore,
+    Collect(&'a mut Vec<ParseError>),
+}
+
+impl Sink<'_> {
+    #[inline]
+    fn push(&mut self, offset: usize, kind: ParseErrorKind) {
+        if let Sink::Collect(errors) = self {
+            errors.push(ParseError { kind, offset });
+        }
+    }
+}
+
+/// Expand all valid entities as if in a text node.
 ///
 /// The WHATWG HTML spec contains the normative reference for
 /// [named entities](https://html.spec.whatwg.org/multipage/named-characters.html#named-character-references).
 /// This is based on the [algorithm described](https://html.spec.whatwg.org/multipage/parsing.html#character-reference-state)
 /// in the WHATWG spec.
 ///
-/// **FIXME [Named character reference state special cases](https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state)**
+/// This is equivalent to [`unescape_in()`] with [`Context::Text`]. Use
+/// [`unescape_in()`] with [`Context::Attribute`] to unescape an attribute
+/// value.
 pub fn unescape<S: AsRef<[u8]>>(escaped: S) -> String {
-    let escaped = escaped.as_ref();
+    unescape_in(escaped, Context::Text)
+}
+
+/// Expand all valid entities in a particular [`Context`].
+///
+/// This implements the [named character reference state](https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state)
+/// special cases: in [`Context::Attribute`] a named reference that did not end
+/// in a semicolon is left untouched when it is immediately followed by `=` or
+/// an ASCII alphanumeric, so that e.g. `?a=1&copy=2` is not mangled.
+pub fn unescape_in<S: AsRef<[u8]>>(escaped: S, context: Context) -> String {
+    unescape_impl(escaped.as_ref(), context, &mut Sink::Ignore)
+}
+
+/// Expand all valid entities as if in a text node, collecting parse errors.
+///
+/// The expansion is identical to [`unescape()`]; in addition, every
+/// recoverable parse error of a kind listed in [`ParseErrorKind`] is recorded
+/// in the returned `Vec`, in the order it occurred. An `&` that does not start
+/// a recognized reference (an unknown name, or `&#` without digits) records no
+/// error. This lets linters and validators report problems without changing
+/// the lenient expansion behavior.
+pub fn unescape_with_errors<S: AsRef<[u8]>>(
+    escaped: S,
+) -> (String, Vec<ParseError>) {
+    let mut errors = Vec::new();
+    let unescaped =
+        unescape_impl(escaped.as_ref(), Context::Text, &mut Sink::Collect(&mut errors));
+    (unescaped, errors)
+}
+
+fn unescape_impl(
+    escaped: &[u8],
+    context: Context,
+    sink: &mut Sink,
+) -> String {
     let mut iter = escaped.iter().peekable();
     let mut buffer = Vec::new(); // FIXME Vec::with_capacity(escaped.len())? Shrink on return?
+    let mut offset = 0; // Byte offset of the next byte to be consumed.
 
     while let Some(c) = iter.next() {
         if *c == b'&' {
-            let mut expansion = match_entity(&mut iter);
+            let amp_offset = offset;
+            offset += 1;
+
+            // `Peekable<slice::Iter>` is an `ExactSizeIterator`, so the number
+            // of bytes `match_entity` consumes is the drop in its length.
+            let before = iter.len();
+            let mut expansion =
+                match_entity(&mut iter, context, amp_offset, sink);
+            offset += before - iter.len();
+
             buffer.append(&mut expansion);
         } else {
+            offset += 1;
             buffer.push(*c);
         }
     }
@@ -37,10 +171,134 @@ pub fn unescape<S: AsRef<[u8]>>(escaped: S) -> String {
     String::from_utf8(buffer).unwrap()
 }
 
+/// Expand all valid entities as if in a text node, returning [`Cow::Borrowed`]
+/// when the input contains no `&`.
+///
+/// Input without an `&` cannot contain an entity, so it is handed back without
+/// allocating or revalidating as UTF-8. See [`unescape()`].
+pub fn unescape_cow(escaped: &str) -> Cow<'_, str> {
+    if !escaped.as_bytes().contains(&b'&') {
+        return Cow::Borrowed(escaped);
+    }
+
+    Cow::Owned(unescape_in(escaped, Context::Text))
+}
+
+/// Expand all valid entities, reading from `input` and writing to `output`.
+///
+/// This processes the input incrementally so that arbitrarily large documents
+/// can be unescaped with bounded memory. An entity split across a read boundary
+/// (e.g. `&am` then `p;`) is held back until the following bytes arrive, and
+/// the longest-match state is flushed at EOF.
+pub fn unescape_stream<R: Read, W: Write>(
+    input: R,
+    output: W,
+) -> io::Result<()> {
+    const CHUNK: usize = 8 * 1024;
+
+    let mut reader = BufReader::new(input);
+    let mut writer = BufWriter::new(output);
+    let mut carry: Vec<u8> = Vec::new();
+    let mut chunk = [0u8; CHUNK];
+
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        carry.extend_from_slice(&chunk[..read]);
+
+        // Expand everything that cannot still be extended by a later read. A
+        // reference begun by `&` near the end of the buffer might continue in
+        // the next chunk, so those bytes stay in `carry`.
+        let split = safe_split(&carry);
+        if split > 0 {
+            writer.write_all(unescape(&carry[..split]).as_bytes())?;
+            carry.drain(..split);
+        }
+    }
+
+    // EOF: expand whatever remains, including any reference at the very end.
+    if !carry.is_empty() {
+        writer.write_all(unescape(&carry).as_bytes())?;
+    }
+
+    writer.flush()
+}
+
+/// Length of the prefix of `buf` that can be expanded without waiting for more
+/// input.
+///
+/// The split point is either the whole buffer (when any trailing reference is
+/// already complete) or the offset of a trailing `&` whose reference might
+/// continue in the next read, moved back before any incomplete UTF-8 sequence
+/// at the end of the buffer. No entity ever straddles it.
+fn safe_split(buf: &[u8]) -> usize {
+    let split = match buf.iter().rposition(|&b| b == b'&') {
+        None => buf.len(),
+        Some(start) if reference_token_complete(&buf[start..]) => buf.len(),
+        Some(start) => start,
+    };
+
+    // A character split by the read boundary is completed by the next read.
+    match std::str::from_utf8(&buf[..split]) {
+        Err(error) if error.error_len().is_none() => error.valid_up_to(),
+        _ => split,
+    }
+}
+
+/// Whether the reference token beginning with `&` is fully present in `token`.
+///
+/// “Complete” means more bytes cannot change how [`match_entity()`] resolves
+/// it: a terminator has been seen, or the run is already longer than any
+/// possible entity.
+fn reference_token_complete(token: &[u8]) -> bool {
+    debug_assert_eq!(token.first(), Some(&b'&'));
+
+    if let Some(&b'#') = token.get(1) {
+        // Numeric reference.
+        let hex = matches!(token.get(2), Some(&b'x') | Some(&b'X'));
+        let mut k = if hex { 3 } else { 2 };
+        while k < token.len() {
+            let digit = if hex {
+                token[k].is_ascii_hexdigit()
+            } else {
+                token[k].is_ascii_digit()
+            };
+            if !digit {
+                // A terminator (`;` or anything else) ends the reference.
+                return true;
+            }
+            k += 1;
+        }
+        return false;
+    }
+
+    // Named reference: a run of alphanumerics, optionally closed by `;`.
+    let mut k = 1;
+    while k < token.len() {
+        if !token[k].is_ascii_alphanumeric() {
+            return true;
+        }
+        k += 1;
+        if k >= ENTITY_MAX_LENGTH {
+            // Already longer than any entity; trailing bytes are emitted
+            // verbatim and cannot change the match.
+            return true;
+        }
+    }
+
+    false
+}
+
 const PEEK_MATCH_ERROR: &str = "iter.next() did not match previous iter.peek()";
 
 #[allow(clippy::from_str_radix_10)]
-fn match_numeric_entity<'a, I>(iter: &mut Peekable<I>) -> Vec<u8>
+fn match_numeric_entity<'a, I>(
+    iter: &mut Peekable<I>,
+    offset: usize,
+    sink: &mut Sink,
+) -> Vec<u8>
 where
     I: Iterator<Item = &'a u8>,
 {
@@ -79,10 +337,17 @@ where
     } else {
         // missing-semicolon-after-character-reference: end the entity anyway.
         // https://html.spec.whatwg.org/multipage/parsing.html#parse-error-missing-semicolon-after-character-reference
+        if number.is_ok() {
+            sink.push(offset, ParseErrorKind::MissingSemicolon);
+        }
     }
 
     if let Ok(number) = number {
-        if let Some(expansion) = correct_numeric_entity(number) {
+        let (expansion, error) = correct_numeric_entity(number);
+        if let Some(error) = error {
+            sink.push(offset, error);
+        }
+        if let Some(expansion) = expansion {
             return expansion;
         }
     }
@@ -168,7 +433,14 @@ fn is_ascii_whitespace<C: Into<u32>>(c: C) -> bool {
 }
 
 // https://html.spec.whatwg.org/multipage/parsing.html#numeric-character-reference-end-state
-fn correct_numeric_entity(number: u32) -> Option<Vec<u8>> {
+//
+// Returns the expansion (if any) alongside the parse error the number
+// triggered, so callers can both recover leniently and report the problem.
+fn correct_numeric_entity(
+    number: u32,
+) -> (Option<Vec<u8>>, Option<ParseErrorKind>) {
+    use ParseErrorKind::*;
+
     #[inline]
     fn char_to_vecu8(c: char) -> Option<Vec<u8>> {
         Some(c.to_string().into())
@@ -181,55 +453,60 @@ fn correct_numeric_entity(number: u32) -> Option<Vec<u8>> {
 
     match number {
         // null-character-reference parse error:
-        0x00 => char_to_vecu8(REPLACEMENT_CHAR),
+        0x00 => (char_to_vecu8(REPLACEMENT_CHAR), Some(NullCharacterReference)),
 
         // character-reference-outside-unicode-range parse error:
-        c if is_outside_range(c) => char_to_vecu8(REPLACEMENT_CHAR),
+        c if is_outside_range(c) => (
+            char_to_vecu8(REPLACEMENT_CHAR),
+            Some(CharacterReferenceOutsideUnicodeRange),
+        ),
 
         // surrogate-character-reference parse error:
-        c if is_surrogate(c) => char_to_vecu8(REPLACEMENT_CHAR),
+        c if is_surrogate(c) => {
+            (char_to_vecu8(REPLACEMENT_CHAR), Some(SurrogateCharacterReference))
+        }
 
         // noncharacter-character-reference parse error:
-        c if is_noncharacter(c) => None,
+        c if is_noncharacter(c) => (None, Some(NoncharacterCharacterReference)),
 
         // control-character-reference parse error exceptions:
-        0x80 => u32_to_vecu8(0x20AC), // EURO SIGN (€)
-        0x82 => u32_to_vecu8(0x201A), // SINGLE LOW-9 QUOTATION MARK (‚)
-        0x83 => u32_to_vecu8(0x0192), // LATIN SMALL LETTER F WITH HOOK (ƒ)
-        0x84 => u32_to_vecu8(0x201E), // DOUBLE LOW-9 QUOTATION MARK („)
-        0x85 => u32_to_vecu8(0x2026), // HORIZONTAL ELLIPSIS (…)
-        0x86 => u32_to_vecu8(0x2020), // DAGGER (†)
-        0x87 => u32_to_vecu8(0x2021), // DOUBLE DAGGER (‡)
-        0x88 => u32_to_vecu8(0x02C6), // MODIFIER LETTER CIRCUMFLEX ACCENT (ˆ)
-        0x89 => u32_to_vecu8(0x2030), // PER MILLE SIGN (‰)
-        0x8A => u32_to_vecu8(0x0160), // LATIN CAPITAL LETTER S WITH CARON (Š)
-        0x8B => u32_to_vecu8(0x2039), // SINGLE LEFT-POINTING ANGLE QUOTATION MARK (‹)
-        0x8C => u32_to_vecu8(0x0152), // LATIN CAPITAL LIGATURE OE (Œ)
-        0x8E => u32_to_vecu8(0x017D), // LATIN CAPITAL LETTER Z WITH CARON (Ž)
-        0x91 => u32_to_vecu8(0x2018), // LEFT SINGLE QUOTATION MARK (‘)
-        0x92 => u32_to_vecu8(0x2019), // RIGHT SINGLE QUOTATION MARK (’)
-        0x93 => u32_to_vecu8(0x201C), // LEFT DOUBLE QUOTATION MARK (“)
-        0x94 => u32_to_vecu8(0x201D), // RIGHT DOUBLE QUOTATION MARK (”)
-        0x95 => u32_to_vecu8(0x2022), // BULLET (•)
-        0x96 => u32_to_vecu8(0x2013), // EN DASH (–)
-        0x97 => u32_to_vecu8(0x2014), // EM DASH (—)
-        0x98 => u32_to_vecu8(0x02DC), // SMALL TILDE (˜)
-        0x99 => u32_to_vecu8(0x2122), // TRADE MARK SIGN (™)
-        0x9A => u32_to_vecu8(0x0161), // LATIN SMALL LETTER S WITH CARON (š)
-        0x9B => u32_to_vecu8(0x203A), // SINGLE RIGHT-POINTING ANGLE QUOTATION MARK (›)
-        0x9C => u32_to_vecu8(0x0153), // LATIN SMALL LIGATURE OE (œ)
-        0x9E => u32_to_vecu8(0x017E), // LATIN SMALL LETTER Z WITH CARON (ž)
-        0x9F => u32_to_vecu8(0x0178), // LATIN CAPITAL LETTER Y WITH DIAERESIS (Ÿ)
+        0x80 => (u32_to_vecu8(0x20AC), Some(ControlCharacterReference)), // EURO SIGN (€)
+        0x82 => (u32_to_vecu8(0x201A), Some(ControlCharacterReference)), // SINGLE LOW-9 QUOTATION MARK (‚)
+        0x83 => (u32_to_vecu8(0x0192), Some(ControlCharacterReference)), // LATIN SMALL LETTER F WITH HOOK (ƒ)
+        0x84 => (u32_to_vecu8(0x201E), Some(ControlCharacterReference)), // DOUBLE LOW-9 QUOTATION MARK („)
+        0x85 => (u32_to_vecu8(0x2026), Some(ControlCharacterReference)), // HORIZONTAL ELLIPSIS (…)
+        0x86 => (u32_to_vecu8(0x2020), Some(ControlCharacterReference)), // DAGGER (†)
+        0x87 => (u32_to_vecu8(0x2021), Some(ControlCharacterReference)), // DOUBLE DAGGER (‡)
+        0x88 => (u32_to_vecu8(0x02C6), Some(ControlCharacterReference)), // MODIFIER LETTER CIRCUMFLEX ACCENT (ˆ)
+        0x89 => (u32_to_vecu8(0x2030), Some(ControlCharacterReference)), // PER MILLE SIGN (‰)
+        0x8A => (u32_to_vecu8(0x0160), Some(ControlCharacterReference)), // LATIN CAPITAL LETTER S WITH CARON (Š)
+        0x8B => (u32_to_vecu8(0x2039), Some(ControlCharacterReference)), // SINGLE LEFT-POINTING ANGLE QUOTATION MARK (‹)
+        0x8C => (u32_to_vecu8(0x0152), Some(ControlCharacterReference)), // LATIN CAPITAL LIGATURE OE (Œ)
+        0x8E => (u32_to_vecu8(0x017D), Some(ControlCharacterReference)), // LATIN CAPITAL LETTER Z WITH CARON (Ž)
+        0x91 => (u32_to_vecu8(0x2018), Some(ControlCharacterReference)), // LEFT SINGLE QUOTATION MARK (‘)
+        0x92 => (u32_to_vecu8(0x2019), Some(ControlCharacterReference)), // RIGHT SINGLE QUOTATION MARK (’)
+        0x93 => (u32_to_vecu8(0x201C), Some(ControlCharacterReference)), // LEFT DOUBLE QUOTATION MARK (“)
+        0x94 => (u32_to_vecu8(0x201D), Some(ControlCharacterReference)), // RIGHT DOUBLE QUOTATION MARK (”)
+        0x95 => (u32_to_vecu8(0x2022), Some(ControlCharacterReference)), // BULLET (•)
+        0x96 => (u32_to_vecu8(0x2013), Some(ControlCharacterReference)), // EN DASH (–)
+        0x97 => (u32_to_vecu8(0x2014), Some(ControlCharacterReference)), // EM DASH (—)
+        0x98 => (u32_to_vecu8(0x02DC), Some(ControlCharacterReference)), // SMALL TILDE (˜)
+        0x99 => (u32_to_vecu8(0x2122), Some(ControlCharacterReference)), // TRADE MARK SIGN (™)
+        0x9A => (u32_to_vecu8(0x0161), Some(ControlCharacterReference)), // LATIN SMALL LETTER S WITH CARON (š)
+        0x9B => (u32_to_vecu8(0x203A), Some(ControlCharacterReference)), // SINGLE RIGHT-POINTING ANGLE QUOTATION MARK (›)
+        0x9C => (u32_to_vecu8(0x0153), Some(ControlCharacterReference)), // LATIN SMALL LIGATURE OE (œ)
+        0x9E => (u32_to_vecu8(0x017E), Some(ControlCharacterReference)), // LATIN SMALL LETTER Z WITH CARON (ž)
+        0x9F => (u32_to_vecu8(0x0178), Some(ControlCharacterReference)), // LATIN CAPITAL LETTER Y WITH DIAERESIS (Ÿ)
 
         // control-character-reference parse error:
-        0x0D => None,
-        c if is_ascii_whitespace(c) => u32_to_vecu8(c),
-        c if is_control(c) => None,
+        0x0D => (None, Some(ControlCharacterReference)),
+        c if is_ascii_whitespace(c) => (u32_to_vecu8(c), None),
+        c if is_control(c) => (None, Some(ControlCharacterReference)),
 
         // Everything else.
         c => match char::from_u32(c) {
-            Some(c) => char_to_vecu8(c),
-            None => None,
+            Some(c) => (char_to_vecu8(c), None),
+            None => (None, None),
         },
     }
 }
@@ -256,58 +533,150 @@ macro_rules! consumer {
 
 consumer!(consume_decimal, b'0'..=b'9');
 consumer!(consume_hexadecimal, b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F');
-consumer!(consume_alphanumeric, b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z');
 
-fn match_entity<'a, I>(iter: &mut Peekable<I>) -> Vec<u8>
+fn match_entity<'a, I>(
+    iter: &mut Peekable<I>,
+    context: Context,
+    offset: usize,
+    sink: &mut Sink,
+) -> Vec<u8>
 where
     I: Iterator<Item = &'a u8>,
 {
     if let Some(&b'#') = iter.peek() {
         // Numeric entity.
-        return match_numeric_entity(iter);
+        return match_numeric_entity(iter, offset, sink);
     }
 
-    // Determine longest possible candidate including & and any trailing ;.
-    let mut candidate = vec![b'&'];
-    candidate.append(&mut consume_alphanumeric(iter));
-
-    if let Some(&b';') = iter.peek() {
-        // Actually consume the semicolon.
-        candidate.push(*iter.next().expect(PEEK_MATCH_ERROR));
-    }
-
-    if candidate.len() < ENTITY_MIN_LENGTH {
-        // Couldn’t possibly match.
-        return candidate;
+    // Walk the trie one byte at a time, following the edge for the next input
+    // byte and recording every terminal node as the latest valid match. We may
+    // over-consume bytes past the match (e.g. the trailing `b` in `&timesb`
+    // when only `&times` matched); those are handed back in the returned
+    // expansion, preserving the longest-valid-match and prefix-entity
+    // semantics (`&times` vs `&timesb;`).
+    let mut node = 0; // Root.
+    let mut consumed: Vec<u8> = Vec::new();
+    let mut best: Option<(usize, &'static str)> = None;
+
+    while let Some(&&byte) = iter.peek() {
+        let edges = ENTITY_NODES[node].edges;
+        match edges.binary_search_by_key(&byte, |&(edge, _)| edge) {
+            Ok(index) => {
+                node = edges[index].1 as usize;
+                consumed.push(byte);
+                iter.next();
+                if let Some(expansion) = ENTITY_NODES[node].expansion {
+                    best = Some((consumed.len(), expansion));
+                }
+            }
+            Err(_) => break,
+        }
     }
 
-    // Find longest matching entity.
-    let max_len = min(candidate.len(), ENTITY_MAX_LENGTH);
-    for check_len in (ENTITY_MIN_LENGTH..=max_len).rev() {
-        if let Some(expansion) = ENTITIES.get(&candidate[..check_len]) {
-            // Found a match.
-            let mut result = Vec::with_capacity(
-                expansion.len() + candidate.len() - check_len,
-            );
-            result.extend_from_slice(expansion);
-
-            if check_len < candidate.len() {
-                // Need to append the rest of the consumed bytes.
-                result.extend_from_slice(&candidate[check_len..]);
-            }
+    let (match_len, expansion) = match best {
+        Some(best) => best,
+        None => {
+            // Did not find a match: emit `&` and the consumed bytes verbatim.
+            let mut result = vec![b'&'];
+            result.extend_from_slice(&consumed);
+            return result;
+        }
+    };
 
+    if consumed[match_len - 1] != b';' {
+        // A reference terminated by “;” always expands; one without a trailing
+        // semicolon is subject to the named character reference state special
+        // case in an attribute value.
+        if context == Context::Attribute
+            && abandon_in_attribute(&consumed, match_len, iter)
+        {
+            // Emit `&` and the consumed bytes verbatim rather than expanding.
+            let mut result = vec![b'&'];
+            result.extend_from_slice(&consumed);
             return result;
         }
+
+        // missing-semicolon-after-character-reference: the named reference was
+        // expanded despite lacking a trailing semicolon.
+        sink.push(offset, ParseErrorKind::MissingSemicolon);
     }
 
-    // Did not find a match.
-    candidate
+    // Emit the expansion followed by any over-consumed trailing bytes.
+    let mut result =
+        Vec::with_capacity(expansion.len() + consumed.len() - match_len);
+    result.extend_from_slice(expansion.as_bytes());
+    result.extend_from_slice(&consumed[match_len..]);
+    result
+}
+
+/// Whether a semicolon-less named reference must be abandoned in an attribute.
+///
+/// Per the [named character reference state](https://html.spec.whatwg.org/multipage/parsing.html#named-character-reference-state),
+/// a match that did not end in a semicolon is abandoned when the byte right
+/// after it is `=` or an ASCII alphanumeric. That following byte is either the
+/// next over-consumed byte in `consumed` or, if the match reached the end of
+/// it, the next unconsumed byte peeked from `iter`.
+fn abandon_in_attribute<'a, I>(
+    consumed: &[u8],
+    match_len: usize,
+    iter: &mut Peekable<I>,
+) -> bool
+where
+    I: Iterator<Item = &'a u8>,
+{
+    let next = if match_len < consumed.len() {
+        Some(consumed[match_len])
+    } else {
+        iter.peek().map(|c| **c)
+    };
+
+    matches!(next, Some(b'=') | Some(b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z'))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// A reader that hands out at most `chunk` bytes per `read`, so tests can
+    /// force entities to straddle read boundaries.
+    struct ChunkReader<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        pos: usize,
+    }
+
+    impl std::io::Read for ChunkReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = std::cmp::min(
+                self.chunk,
+                std::cmp::min(buf.len(), self.data.len() - self.pos),
+            );
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    fn unescape_stream_chunked(input: &str, chunk: usize) -> String {
+        let reader = ChunkReader { data: input.as_bytes(), chunk, pos: 0 };
+        let mut output = Vec::new();
+        unescape_stream(reader, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    test!(
+        stream_split_named,
+        unescape_stream_chunked("x &amp; y", 2) == "x & y"
+    );
+    test!(
+        stream_split_numeric,
+        unescape_stream_chunked("&#x7a;z", 1) == "zz"
+    );
+    test!(
+        stream_no_semicolon_eof,
+        unescape_stream_chunked("&times", 3) == "×"
+    );
+
     test!(almost_entity, unescape("&time") == "&time");
     test!(exact_no_semicolon, unescape("&times") == "×");
     test!(exact, unescape("&times;") == "×");
@@ -351,6 +720,73 @@ mod tests {
     );
     test!(special_entity_space, unescape("&#x20") == " ");
 
+    test!(attr_no_semicolon_eq_text, unescape("&amp=") == "&=");
+    test!(
+        attr_no_semicolon_eq_attribute,
+        unescape_in("&amp=", Context::Attribute) == "&amp="
+    );
+    test!(attr_semicolon_text, unescape("&amp;") == "&");
+    test!(
+        attr_semicolon_attribute,
+        unescape_in("&amp;", Context::Attribute) == "&"
+    );
+    test!(attr_alnum_text, unescape("&ampa") == "&a");
+    test!(
+        attr_alnum_attribute,
+        unescape_in("&ampa", Context::Attribute) == "&ampa"
+    );
+    test!(
+        attr_query_string,
+        unescape_in("?a=1&copy=2", Context::Attribute) == "?a=1&copy=2"
+    );
+
+    test!(
+        unescape_cow_borrows_clean,
+        matches!(unescape_cow("no entities"), Cow::Borrowed("no entities"))
+    );
+    test!(
+        unescape_cow_owns_entity,
+        matches!(unescape_cow("&amp;"), Cow::Owned(s) if s == "&")
+    );
+
+    test!(
+        errors_none,
+        unescape_with_errors("&amp;") == ("&".to_string(), Vec::new())
+    );
+    test!(
+        errors_missing_semicolon_named,
+        unescape_with_errors("&amp")
+            == (
+                "&".to_string(),
+                vec![ParseError {
+                    kind: ParseErrorKind::MissingSemicolon,
+                    offset: 0,
+                }]
+            )
+    );
+    test!(
+        errors_missing_semicolon_numeric,
+        unescape_with_errors("abc&#122")
+            == (
+                "abcz".to_string(),
+                vec![ParseError {
+                    kind: ParseErrorKind::MissingSemicolon,
+                    offset: 3,
+                }]
+            )
+    );
+    test!(
+        errors_null,
+        unescape_with_errors("&#0;")
+            == (
+                "\u{fffd}".to_string(),
+                vec![ParseError {
+                    kind: ParseErrorKind::NullCharacterReference,
+                    offset: 0,
+                }]
+            )
+    );
+
     const ALL_SOURCE: &str =
         include_str!("../tests/corpus/all-entities-source.txt");
     const ALL_EXPANDED: &str =